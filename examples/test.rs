@@ -10,7 +10,7 @@ fn main() {
     {
         if let Ok(res) = usb2snes.get_memory(0xF50000, 2048) {
             if res.len() >= 7 {
-                println!("current room rmb       {:x}{:x}", res[0x79b + 1],res[0x79b + 0]);
+                println!("current room rmb       {:x}{:x}", res[0x79b + 1],res[0x79b]);
             }
         }
 
@@ -0,0 +1,115 @@
+//! An async wrapper around the blocking [`Usb2snes`] device.
+//!
+//! `rusb`'s bulk transfers are synchronous and block the calling thread for up to the request
+//! timeout. To keep that off a polling loop's thread, [`AsyncUsb2snes`] owns the device on a
+//! background worker thread and hands back futures that a caller can `await` without blocking its
+//! own thread.
+//!
+//! # Limitations
+//!
+//! This is a *serialized* wrapper, not a concurrent one. All requests funnel through a single
+//! worker thread and run one at a time, so a slow or stuck transfer head-of-line-blocks every
+//! request queued behind it. Cancellation is likewise best-effort: dropping a future only skips a
+//! request that is still *queued* (see the [`oneshot::Sender::is_canceled`] check in the worker);
+//! once the underlying `read_bulk`/`write_bulk` is in flight it runs to completion, and a
+//! [`with_timeout`] that resolves to [`Error::Timeout`] leaves the worker busy finishing the stale
+//! transfer before it can service the next request. True concurrency and mid-transfer cancellation
+//! would require the libusb async transfer API, which `rusb` does not yet expose.
+
+use std::sync::mpsc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use rusb::{Error, Result};
+
+use crate::Usb2snes;
+
+enum Request {
+    Get {
+        offset: u32,
+        size: u32,
+        reply: oneshot::Sender<Result<Vec<u8>>>,
+    },
+    Put {
+        offset: u32,
+        data: Vec<u8>,
+        reply: oneshot::Sender<Result<()>>,
+    },
+}
+
+/// An async handle to a device running on a dedicated worker thread.
+pub struct AsyncUsb2snes {
+    sender: mpsc::Sender<Request>,
+    _worker: JoinHandle<()>,
+}
+
+impl AsyncUsb2snes {
+    /// Moves `device` onto a background thread and returns a handle that dispatches bulk transfers
+    /// to it asynchronously.
+    pub fn new(device: Usb2snes) -> AsyncUsb2snes {
+        let (sender, receiver) = mpsc::channel::<Request>();
+
+        let worker = std::thread::spawn(move || {
+            for request in receiver {
+                match request {
+                    Request::Get { offset, size, reply } => {
+                        // The future was dropped while queued, skip the transfer entirely.
+                        if reply.is_canceled() {
+                            continue;
+                        }
+                        let _ = reply.send(device.get_memory(offset, size));
+                    }
+                    Request::Put { offset, data, reply } => {
+                        if reply.is_canceled() {
+                            continue;
+                        }
+                        let _ = reply.send(device.put_memory(offset, &data));
+                    }
+                }
+            }
+        });
+
+        AsyncUsb2snes { sender, _worker: worker }
+    }
+
+    /// Reads `size` bytes at `offset`, bounded by `timeout`. The returned future resolves when the
+    /// worker has finished the transfer; dropping it only cancels the request while it is still
+    /// queued (see the module-level limitations).
+    pub async fn get_memory(&self, offset: u32, size: u32, timeout: Duration) -> Result<Vec<u8>> {
+        let (reply, receiver) = oneshot::channel();
+
+        if self.sender.send(Request::Get { offset, size, reply }).is_err() {
+            return Err(Error::NoDevice);
+        }
+
+        with_timeout(receiver, timeout).await
+    }
+
+    /// Writes `data` at `offset`, bounded by `timeout`. Dropping the future only cancels the
+    /// request while it is still queued (see the module-level limitations).
+    pub async fn put_memory(&self, offset: u32, data: Vec<u8>, timeout: Duration) -> Result<()> {
+        let (reply, receiver) = oneshot::channel();
+
+        if self.sender.send(Request::Put { offset, data, reply }).is_err() {
+            return Err(Error::NoDevice);
+        }
+
+        with_timeout(receiver, timeout).await
+    }
+}
+
+/// Races the worker's reply against `timeout`, surfacing [`Error::Timeout`] if the deadline wins and
+/// [`Error::NoDevice`] if the worker dropped the reply channel. Note that an [`Error::Timeout`] only
+/// abandons the *wait*: the worker keeps running the in-flight transfer to completion.
+async fn with_timeout<T>(receiver: oneshot::Receiver<Result<T>>, timeout: Duration) -> Result<T> {
+    use futures::future::{select, Either};
+
+    futures::pin_mut!(receiver);
+
+    match select(receiver, futures_timer::Delay::new(timeout)).await {
+        Either::Left((Ok(result), _)) => result,
+        Either::Left((Err(_canceled), _)) => Err(Error::NoDevice),
+        Either::Right(_) => Err(Error::Timeout),
+    }
+}
@@ -1,21 +1,22 @@
-pub use libusb;
+pub use rusb;
+
+pub mod server;
+pub mod async_api;
 
 use std::time::Duration;
-use libusb::{Context, Direction, Error, Device, TransferType, DeviceDescriptor, Result};
+use rusb::{Context, Direction, Error, Device, TransferType, DeviceDescriptor, Result, UsbContext};
 
 const VENDOR_ID:u16 = 0x1209;     // InterBiometrics
 const PRODUCT_ID:u16 = 0x5a22;    // ikari_01 sd2snes
 
 #[derive(Debug, Default, Clone, Copy)]
 struct Endpoint {
-    config: u8,
     iface: u8,
-    setting: u8,
     address: u8
 }
 
-pub struct Usb2snes<'a> {
-    handle: libusb::DeviceHandle<'a>,
+pub struct Usb2snes {
+    handle: rusb::DeviceHandle<Context>,
     endpoint_in: Endpoint,
     endpoint_out: Endpoint,
 }
@@ -72,16 +73,46 @@ pub enum Flags
 }
 
 
-impl<'a> Usb2snes<'a> {
+///
+/// A decoded `USBA` response block as returned by the device. `size` is the payload length the
+/// device advertises in bytes 252..256 of the header; `data` holds the payload that followed the
+/// header (when any).
+///
+pub struct Response {
+    pub flags: u8,
+    pub error: u8,
+    pub size: u32,
+    pub data: Vec<u8>,
+}
+
+///
+/// Typed result of the `Info` request: the firmware version string, the device/flash name and the
+/// name of the ROM that is currently running (empty when sitting in the menu).
+///
+pub struct Info {
+    pub firmware_version: String,
+    pub device_name: String,
+    pub rom_name: String,
+}
+
+///
+/// A single entry in an SD card directory listing as returned by [`Usb2snes::ls`].
+///
+pub struct DirEntry {
+    pub name: String,
+    pub is_dir: bool,
+}
+
+impl Usb2snes {
     ///
     /// Creates a Usb2snes instance. This function will assume the default Vendor Id (0x1209) and
     /// Product Id (0x5a22) for the SD2SNES USB connection.
     ///
-    pub fn new(context: &'a Context) -> Result<Usb2snes<'a>> {
+    pub fn new(context: &Context) -> Result<Usb2snes> {
         Self::new_from_vid_pid(context, VENDOR_ID, PRODUCT_ID)
     }
 
-    pub fn new_from_vid_pid(context: &'a Context, vendor_id: u16, product_id: u16) -> Result<Usb2snes<'a>> {
+    pub fn new_from_vid_pid(context: &Context, vendor_id: u16, product_id: u16) -> Result<Usb2snes> {
         let (mut device, desc, mut handle) = Self::open_device(context, vendor_id, product_id)?;
 
         // Try bulk based
@@ -104,7 +135,7 @@ impl<'a> Usb2snes<'a> {
 
             if let Some(ends) = endpoints {
                 return Ok(Usb2snes {
-                    handle: handle,
+                    handle,
                     endpoint_in: ends.0,
                     endpoint_out: ends.1,
                 });
@@ -117,8 +148,8 @@ impl<'a> Usb2snes<'a> {
         Err(Error::Other)
     }
 
-    fn open_device(context: &'a libusb::Context, vid: u16, pid: u16) ->
-        Result<(libusb::Device<'a>, libusb::DeviceDescriptor, libusb::DeviceHandle<'a>)>
+    fn open_device(context: &Context, vid: u16, pid: u16) ->
+        Result<(rusb::Device<Context>, rusb::DeviceDescriptor, rusb::DeviceHandle<Context>)>
     {
         let devices = context.devices()?;
 
@@ -141,7 +172,7 @@ impl<'a> Usb2snes<'a> {
         Err(Error::Other)
     }
 
-    fn configure_endpoint(handle: &mut libusb::DeviceHandle, endpoint: &Endpoint) -> libusb::Result<()> {
+    fn configure_endpoint(handle: &mut rusb::DeviceHandle<Context>, endpoint: &Endpoint) -> Result<()> {
         let has_kernel_driver = match handle.kernel_driver_active(endpoint.iface) {
             Ok(true) => {
                 println!("Detaching kernel driver");
@@ -164,7 +195,7 @@ impl<'a> Usb2snes<'a> {
         Ok(())
     }
 
-    fn get_end_points(device: &mut Device, device_desc: &DeviceDescriptor, transfer_type: TransferType) -> Option<(Endpoint, Endpoint)> {
+    fn get_end_points(device: &mut Device<Context>, device_desc: &DeviceDescriptor, transfer_type: TransferType) -> Option<(Endpoint, Endpoint)> {
         let mut endpoint_in = None;
         let mut endpoint_out = None;
 
@@ -183,16 +214,12 @@ impl<'a> Usb2snes<'a> {
 
                         if endpoint_desc.direction() == Direction::In {
                             endpoint_in = Some(Endpoint {
-                                config: config_desc.number(),
                                 iface: interface_desc.interface_number(),
-                                setting: interface_desc.setting_number(),
                                 address: endpoint_desc.address()
                             });
                         } else if endpoint_desc.direction() == Direction::Out {
                             endpoint_out = Some(Endpoint {
-                                config: config_desc.number(),
                                 iface: interface_desc.interface_number(),
-                                setting: interface_desc.setting_number(),
                                 address: endpoint_desc.address()
                             });
                         }
@@ -201,10 +228,9 @@ impl<'a> Usb2snes<'a> {
             }
         }
 
-        if endpoint_in.is_some() && endpoint_out.is_some() {
-            Some((endpoint_in.unwrap(), endpoint_out.unwrap()))
-        } else {
-            None
+        match (endpoint_in, endpoint_out) {
+            (Some(endpoint_in), Some(endpoint_out)) => Some((endpoint_in, endpoint_out)),
+            _ => None
         }
     }
 
@@ -231,13 +257,13 @@ impl<'a> Usb2snes<'a> {
         command[256] = ((offset >> 24) & 0xff) as u8;
         command[257] = ((offset >> 16) & 0xff) as u8;
         command[258] = ((offset >> 8) & 0xff) as u8;
-        command[259] = ((offset >> 0) & 0xff) as u8;
+        command[259] = (offset & 0xff) as u8;
 
         // size
         command[252] = ((size >> 24) & 0xff) as u8;
         command[253] = ((size >> 16) & 0xff) as u8;
         command[254] = ((size >> 8) & 0xff) as u8;
-        command[255] = ((size >> 0) & 0xff) as u8;
+        command[255] = (size & 0xff) as u8;
 
         self.clear_read();
 
@@ -251,39 +277,515 @@ impl<'a> Usb2snes<'a> {
             }
         }
 
-        let mut fail_counts = 0;
         let mut size_count = size as i32;
         let mut result = Vec::with_capacity(size as usize);
 
-        loop
-        {
+        // Backoff for retryable stalls. We clear the endpoint and retry with an exponentially
+        // growing delay (bounded by a retry budget) instead of spinning a fixed number of times on
+        // a dead pipe.
+        let mut backoff = Duration::from_millis(1);
+        let max_backoff = Duration::from_millis(500);
+        let mut retries_left = 10;
+
+        while size_count > 0 {
             match self.handle.read_bulk(self.endpoint_in.address, &mut output, timeout) {
                 Ok(len) => {
-                    //println!("len back {}", len);
+                    // Only keep what the device actually returned, not the whole 512-byte buffer.
+                    result.extend_from_slice(&output[..len]);
                     size_count -= len as i32;
+                    backoff = Duration::from_millis(1);
+                    retries_left = 10;
+                }
 
-                    for t in output.iter() {
-                        result.push(*t);
+                // A timed-out or stalled endpoint is recoverable: clear the halt, drain any
+                // residual bytes and back off before retrying the outstanding read, up to the
+                // retry budget.
+                Err(err @ Error::Timeout) | Err(err @ Error::Pipe) => {
+                    if retries_left == 0 {
+                        println!("giving up after repeated stalls: {}", err);
+                        return Err(err);
                     }
+
+                    retries_left -= 1;
+                    let _ = self.handle.clear_halt(self.endpoint_in.address);
+                    self.clear_read();
+                    std::thread::sleep(backoff);
+                    backoff = (backoff * 2).min(max_backoff);
+                }
+
+                // A disconnected cart (or any other error) is fatal, surface it immediately.
+                Err(err) => {
+                    println!("fatal read error: {}", err);
+                    return Err(err);
                 }
+            }
+        }
+
+        Ok(result)
+    }
+
+
+    ///
+    /// Writes `data` to the cart starting at `offset`. This mirrors the `Get` framing: a 512-byte
+    /// command header is sent first and then the payload is streamed out on `endpoint_out` in
+    /// 512-byte blocks (the last block is zero-padded).
+    ///
+    pub fn put_memory(&self, offset: u32, data: &[u8]) -> Result<()> {
+        let mut command: [u8; 512] = [0; 512];
+
+        Self::fill_header(&mut command, Opcode::Put);
+
+        let timeout = Duration::from_millis(500);
+
+        let size = data.len() as u32;
+
+        // Memory offset
+        command[256] = ((offset >> 24) & 0xff) as u8;
+        command[257] = ((offset >> 16) & 0xff) as u8;
+        command[258] = ((offset >> 8) & 0xff) as u8;
+        command[259] = (offset & 0xff) as u8;
+
+        // size
+        command[252] = ((size >> 24) & 0xff) as u8;
+        command[253] = ((size >> 16) & 0xff) as u8;
+        command[254] = ((size >> 8) & 0xff) as u8;
+        command[255] = (size & 0xff) as u8;
+
+        match self.handle.write_bulk(self.endpoint_out.address, &command, timeout) {
+            Ok(_) => (),
+            Err(err) => {
+                println!("could not write to endpoint: {}", err);
+                return Err(Error::Other);
+            }
+        }
+
+        // Stream the payload out in 512-byte blocks, zero-padding the last one.
+        let mut block: [u8; 512] = [0; 512];
 
-                Err(_err) => {
-                    fail_counts += 1;
+        for chunk in data.chunks(512) {
+            for t in block.iter_mut() {
+                *t = 0;
+            }
+
+            block[..chunk.len()].copy_from_slice(chunk);
+
+            match self.handle.write_bulk(self.endpoint_out.address, &block, timeout) {
+                Ok(_) => (),
+                Err(err) => {
+                    println!("could not write to endpoint: {}", err);
+                    return Err(Error::Other);
                 }
             }
+        }
+
+        Ok(())
+    }
+
+    ///
+    /// Batched read of up to 8 small, non-contiguous regions in a single round trip. Each
+    /// operation is a `(offset, size)` pair with a 24-bit address and a size of at most 255 bytes.
+    /// The operations are packed into the command header starting at byte 32 as 4-byte
+    /// `[size, addr_hi, addr_mid, addr_lo]` records; the reply is a single 64-byte-aligned block
+    /// that is split back into one slice per requested operation.
+    ///
+    pub fn vget(&self, ops: &[(u32, u8)]) -> Result<Vec<Vec<u8>>> {
+        let mut command: [u8; 512] = [0; 512];
+
+        Self::fill_header(&mut command, Opcode::Vget);
+        command[6] = Flags::Data64b as u8;
+
+        let timeout = Duration::from_millis(500);
+        let mut total = 0usize;
+
+        for (i, (offset, size)) in ops.iter().take(8).enumerate() {
+            let base = 32 + i * 4;
+            command[base] = *size;
+            command[base + 1] = ((offset >> 16) & 0xff) as u8;
+            command[base + 2] = ((offset >> 8) & 0xff) as u8;
+            command[base + 3] = (offset & 0xff) as u8;
+            total += *size as usize;
+        }
 
-            if fail_counts == 1000 {
+        match self.handle.write_bulk(self.endpoint_out.address, &command, timeout) {
+            Ok(_) => (),
+            Err(err) => {
+                println!("could not write to endpoint: {}", err);
                 return Err(Error::Other);
             }
+        }
+
+        // The reply comes back as a single block padded up to a 64-byte boundary.
+        let aligned = (total + 63) & !63;
+        let mut block = vec![0u8; aligned];
+        let mut got = 0;
 
-            if size_count <= 0 {
+        while got < aligned {
+            let len = self.handle.read_bulk(self.endpoint_in.address, &mut block[got..], timeout)?;
+            if len == 0 {
                 break;
             }
+            got += len;
+        }
+
+        // Split the block back into the individually requested slices.
+        let mut result = Vec::with_capacity(ops.len().min(8));
+        let mut pos = 0;
+
+        for (_, size) in ops.iter().take(8) {
+            let end = pos + *size as usize;
+            result.push(block[pos..end].to_vec());
+            pos = end;
         }
 
         Ok(result)
     }
 
+    ///
+    /// Batched write of up to 8 small, non-contiguous regions in a single round trip. Mirrors
+    /// [`vget`](Self::vget): each operation packs a `[size, addr_hi, addr_mid, addr_lo]` record
+    /// into the command header and the payloads are streamed out, concatenated and padded to a
+    /// 64-byte boundary.
+    ///
+    pub fn vput(&self, ops: &[(u32, &[u8])]) -> Result<()> {
+        let mut command: [u8; 512] = [0; 512];
+
+        Self::fill_header(&mut command, Opcode::Vput);
+        command[6] = Flags::Data64b as u8;
+
+        let timeout = Duration::from_millis(500);
+        let mut payload = Vec::new();
+
+        for (i, (offset, data)) in ops.iter().take(8).enumerate() {
+            let base = 32 + i * 4;
+            command[base] = data.len() as u8;
+            command[base + 1] = ((offset >> 16) & 0xff) as u8;
+            command[base + 2] = ((offset >> 8) & 0xff) as u8;
+            command[base + 3] = (offset & 0xff) as u8;
+            payload.extend_from_slice(data);
+        }
+
+        match self.handle.write_bulk(self.endpoint_out.address, &command, timeout) {
+            Ok(_) => (),
+            Err(err) => {
+                println!("could not write to endpoint: {}", err);
+                return Err(Error::Other);
+            }
+        }
+
+        // Pad the concatenated payload up to a 64-byte boundary before streaming it out.
+        payload.resize((payload.len() + 63) & !63, 0);
+
+        match self.handle.write_bulk(self.endpoint_out.address, &payload, timeout) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                println!("could not write to endpoint: {}", err);
+                Err(Error::Other)
+            }
+        }
+    }
+
+    ///
+    /// Resets the currently running game.
+    ///
+    pub fn reset(&self) -> Result<()> {
+        self.send_command(Opcode::Reset)
+    }
+
+    ///
+    /// Boots the ROM at `path` on the SD card. The path is placed in the command block starting at
+    /// byte 256 (NUL-terminated).
+    ///
+    pub fn boot(&self, path: &str) -> Result<()> {
+        let mut command: [u8; 512] = [0; 512];
+
+        Self::fill_header(&mut command, Opcode::Boot);
+        command[5] = Space::File as u8;
+
+        Self::put_path(&mut command, 256, path)?;
+
+        let timeout = Duration::from_millis(500);
+
+        match self.handle.write_bulk(self.endpoint_out.address, &command, timeout) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                println!("could not write to endpoint: {}", err);
+                Err(Error::Other)
+            }
+        }
+    }
+
+    ///
+    /// Power cycles the SD2SNES, returning to the menu.
+    ///
+    pub fn power_cycle(&self) -> Result<()> {
+        self.send_command(Opcode::PowerCycle)
+    }
+
+    ///
+    /// Queries the device for its firmware/running-ROM information. Unlike the fire-and-forget
+    /// operations this clears the `Noresp` flag and decodes the `USBA` acknowledgement into a typed
+    /// [`Info`].
+    ///
+    pub fn info(&self) -> Result<Info> {
+        let mut command: [u8; 512] = [0; 512];
+
+        Self::fill_header(&mut command, Opcode::Info);
+        // We want the response block back, so drop the no-response flag.
+        command[6] = Flags::NoFlag as u8;
+
+        let timeout = Duration::from_millis(500);
+
+        match self.handle.write_bulk(self.endpoint_out.address, &command, timeout) {
+            Ok(_) => (),
+            Err(err) => {
+                println!("could not write to endpoint: {}", err);
+                return Err(Error::Other);
+            }
+        }
+
+        let response = self.read_response()?;
+
+        // The payload is a run of NUL-terminated strings: firmware version, device/flash name and
+        // the currently running ROM (empty in the menu).
+        let mut fields = response.data.split(|b| *b == 0).map(|field| {
+            String::from_utf8_lossy(field).into_owned()
+        });
+
+        Ok(Info {
+            firmware_version: fields.next().unwrap_or_default(),
+            device_name: fields.next().unwrap_or_default(),
+            rom_name: fields.next().unwrap_or_default(),
+        })
+    }
+
+    ///
+    /// Reads a single 512-byte `USBA` response block, validates the magic and opcode and decodes
+    /// the flags, error code and payload size. The payload (if the advertised `size` is non-zero)
+    /// is read in on `endpoint_in` after the header.
+    ///
+    pub fn read_response(&self) -> Result<Response> {
+        let (flags, error, size) = self.read_response_header()?;
+
+        let timeout = Duration::from_millis(500);
+        let mut data = Vec::with_capacity(size as usize);
+        let mut block: [u8; 512] = [0; 512];
+        let mut remaining = size as i32;
+
+        while remaining > 0 {
+            let len = self.handle.read_bulk(self.endpoint_in.address, &mut block, timeout)?;
+            let len = len.min(remaining as usize);
+            data.extend_from_slice(&block[..len]);
+            remaining -= len as i32;
+        }
+
+        Ok(Response { flags, error, size, data })
+    }
+
+    ///
+    /// Reads and validates the leading 512-byte `USBA` response block that every response-bearing
+    /// operation (`Info`, `Ls`, ...) sends ahead of its data phase, returning the decoded
+    /// `(flags, error, size)`. `size` is the big-endian payload length from bytes 252..256.
+    ///
+    fn read_response_header(&self) -> Result<(u8, u8, u32)> {
+        let mut header: [u8; 512] = [0; 512];
+        let timeout = Duration::from_millis(500);
+
+        let len = self.handle.read_bulk(self.endpoint_in.address, &mut header, timeout)?;
+
+        if len < 512 || &header[0..4] != b"USBA" {
+            println!("bad response header");
+            return Err(Error::Other);
+        }
+
+        if header[4] != Opcode::Respose as u8 {
+            println!("unexpected response opcode {}", header[4]);
+            return Err(Error::Other);
+        }
+
+        let flags = header[5];
+        let error = header[6];
+
+        // size is encoded big-endian in bytes 252..256, like get_memory encodes them
+        let size = ((header[252] as u32) << 24)
+            | ((header[253] as u32) << 16)
+            | ((header[254] as u32) << 8)
+            | (header[255] as u32);
+
+        Ok((flags, error, size))
+    }
+
+    ///
+    /// Sends a bare command header with no payload. Used by the operations that only need the
+    /// opcode (`Reset`, `PowerCycle`).
+    ///
+    fn send_command(&self, op_code: Opcode) -> Result<()> {
+        let mut command: [u8; 512] = [0; 512];
+
+        Self::fill_header(&mut command, op_code);
+
+        let timeout = Duration::from_millis(500);
+
+        match self.handle.write_bulk(self.endpoint_out.address, &command, timeout) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                println!("could not write to endpoint: {}", err);
+                Err(Error::Other)
+            }
+        }
+    }
+
+    ///
+    /// Lists the contents of `path` on the SD card. The path is placed in the command block at
+    /// byte 256 with [`Space::File`]. Like every other response-bearing operation the reply opens
+    /// with a `USBA` header block; the streamed, multi-block listing follows it. Each entry is a
+    /// type byte (0 = directory, 1 = file, 0xFF = end-of-list) followed by a NUL-terminated name;
+    /// entries may straddle 512-byte block boundaries.
+    ///
+    pub fn ls(&self, path: &str) -> Result<Vec<DirEntry>> {
+        let mut command: [u8; 512] = [0; 512];
+
+        Self::fill_header(&mut command, Opcode::Ls);
+        command[5] = Space::File as u8;
+        command[6] = Flags::NoFlag as u8;
+
+        Self::put_path(&mut command, 256, path)?;
+
+        let timeout = Duration::from_millis(500);
+
+        match self.handle.write_bulk(self.endpoint_out.address, &command, timeout) {
+            Ok(_) => (),
+            Err(err) => {
+                println!("could not write to endpoint: {}", err);
+                return Err(Error::Other);
+            }
+        }
+
+        // The listing is preceded by the standard USBA response header, same as read_response.
+        self.read_response_header()?;
+
+        let mut entries = Vec::new();
+        let mut block: [u8; 512] = [0; 512];
+
+        // Parse a simple state machine across block boundaries: read a type byte, then accumulate
+        // name bytes up to the NUL terminator.
+        let mut is_dir = false;
+        let mut name: Vec<u8> = Vec::new();
+        let mut expect_type = true;
+        let mut done = false;
+
+        while !done {
+            let len = self.handle.read_bulk(self.endpoint_in.address, &mut block, timeout)?;
+            if len == 0 {
+                break;
+            }
+
+            for &byte in &block[..len] {
+                if expect_type {
+                    is_dir = match byte {
+                        0 => true,
+                        1 => false,
+                        0xff => {
+                            done = true;
+                            break;
+                        }
+                        // Any other type byte means we have lost sync with the stream, bail out
+                        // rather than guessing the entry is a file.
+                        other => {
+                            println!("unknown directory entry type {}", other);
+                            return Err(Error::Other);
+                        }
+                    };
+                    expect_type = false;
+                } else if byte == 0 {
+                    entries.push(DirEntry {
+                        name: String::from_utf8_lossy(&name).into_owned(),
+                        is_dir,
+                    });
+                    name.clear();
+                    expect_type = true;
+                } else {
+                    name.push(byte);
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    ///
+    /// Creates the directory `path` on the SD card.
+    ///
+    pub fn mkdir(&self, path: &str) -> Result<()> {
+        self.file_command(Opcode::Mkdir, path)
+    }
+
+    ///
+    /// Removes the file or directory at `path` on the SD card.
+    ///
+    pub fn rm(&self, path: &str) -> Result<()> {
+        self.file_command(Opcode::Rm, path)
+    }
+
+    ///
+    /// Moves/renames `from` to `to` on the SD card. Both NUL-terminated paths go into the command
+    /// block, `from` at byte 256 and `to` immediately after its terminator.
+    ///
+    pub fn mv(&self, from: &str, to: &str) -> Result<()> {
+        let mut command: [u8; 512] = [0; 512];
+
+        Self::fill_header(&mut command, Opcode::Mv);
+        command[5] = Space::File as u8;
+
+        let dst = Self::put_path(&mut command, 256, from)?;
+        Self::put_path(&mut command, dst, to)?;
+
+        self.write_command(&command)
+    }
+
+    ///
+    /// Sends a single-path filesystem command (`Mkdir`, `Rm`). The path is placed in the command
+    /// block at byte 256 with [`Space::File`].
+    ///
+    fn file_command(&self, op_code: Opcode, path: &str) -> Result<()> {
+        let mut command: [u8; 512] = [0; 512];
+
+        Self::fill_header(&mut command, op_code);
+        command[5] = Space::File as u8;
+
+        Self::put_path(&mut command, 256, path)?;
+
+        self.write_command(&command)
+    }
+
+    ///
+    /// Writes `path` as a NUL-terminated string into `command` at `offset`, returning the index
+    /// just past the terminator. Returns [`Error::InvalidParam`] rather than panicking if the path
+    /// (plus its terminator) would not fit in the 512-byte command block.
+    ///
+    fn put_path(command: &mut [u8], offset: usize, path: &str) -> Result<usize> {
+        let bytes = path.as_bytes();
+
+        if offset + bytes.len() + 1 > command.len() {
+            println!("path too long for command block: {}", path);
+            return Err(Error::InvalidParam);
+        }
+
+        command[offset..offset + bytes.len()].copy_from_slice(bytes);
+        command[offset + bytes.len()] = 0;
+        Ok(offset + bytes.len() + 1)
+    }
+
+    fn write_command(&self, command: &[u8]) -> Result<()> {
+        let timeout = Duration::from_millis(500);
+
+        match self.handle.write_bulk(self.endpoint_out.address, command, timeout) {
+            Ok(_) => Ok(()),
+            Err(err) => {
+                println!("could not write to endpoint: {}", err);
+                Err(Error::Other)
+            }
+        }
+    }
 
     pub fn clear_read(&self) {
         let mut temp: [u8; 64] = [0; 64];
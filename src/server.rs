@@ -0,0 +1,194 @@
+//! A small QUsb2Snes-compatible WebSocket server.
+//!
+//! This re-exports a locally attached SD2SNES over the network the way the usbip example re-exports
+//! a USB device, so the many existing QUsb2Snes clients (emulators, trackers, randomizer tools) can
+//! talk to a real cart through this crate without reimplementing the USB layer. Each incoming JSON
+//! command is translated into the matching [`Usb2snes`] method; binary payloads travel as raw
+//! WebSocket frames after the JSON command.
+//!
+//! A minimal connection handshake (`DeviceList`, `Attach`, `Name`, `AppVersion`) is implemented so
+//! that clients which open with it can reach the data commands; the single attached cart is
+//! surfaced as one device port and `Attach` and `Name` are accepted as no-ops.
+
+use std::net::TcpListener;
+
+use serde_json::{json, Value};
+use tungstenite::{accept, Message};
+
+use crate::Usb2snes;
+
+/// Default port the QUsb2Snes clients expect to find the server on.
+pub const DEFAULT_PORT: u16 = 8080;
+
+/// Serves `device` over the QUsb2Snes WebSocket protocol, blocking forever. Connections are handled
+/// one at a time, which matches the single-device, single-cart model of the underlying USB link.
+pub fn serve(device: &Usb2snes, port: u16) -> std::io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                println!("accept failed: {}", err);
+                continue;
+            }
+        };
+
+        let mut socket = match accept(stream) {
+            Ok(socket) => socket,
+            Err(err) => {
+                println!("websocket handshake failed: {}", err);
+                continue;
+            }
+        };
+
+        loop {
+            let msg = match socket.read() {
+                Ok(msg) => msg,
+                Err(_) => break,
+            };
+
+            let text = match msg {
+                Message::Text(text) => text,
+                Message::Close(_) => break,
+                // Binary frames only arrive as the payload of a PutAddress, which is consumed
+                // inline while handling that command, so anything else here is ignored.
+                _ => continue,
+            };
+
+            let command: Value = match serde_json::from_str(&text) {
+                Ok(command) => command,
+                Err(err) => {
+                    println!("bad command json: {}", err);
+                    continue;
+                }
+            };
+
+            if let Err(err) = handle_command(device, &mut socket, &command) {
+                println!("command failed: {}", err);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn operands(command: &Value) -> Vec<String> {
+    command["Operands"]
+        .as_array()
+        .map(|ops| {
+            ops.iter()
+                .map(|op| op.as_str().unwrap_or_default().to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn parse_hex(value: &str) -> u32 {
+    u32::from_str_radix(value, 16).unwrap_or(0)
+}
+
+fn handle_command<S>(
+    device: &Usb2snes,
+    socket: &mut tungstenite::WebSocket<S>,
+    command: &Value,
+) -> std::io::Result<()>
+where
+    S: std::io::Read + std::io::Write,
+{
+    let opcode = command["Opcode"].as_str().unwrap_or_default();
+    let ops = operands(command);
+
+    match opcode {
+        // Connection handshake. Real trackers/emulators open with these before any data command:
+        // they enumerate the available device ports, attach to one and announce themselves. We
+        // expose the single attached cart as one port and accept the rest as no-ops.
+        "DeviceList" => {
+            let _ = socket.send(Message::Text(
+                json!({ "Results": ["usb2snes"] }).to_string(),
+            ));
+        }
+
+        // Attach <port> and Name <app> set per-connection state the client does not expect a reply
+        // to; AppVersion is answered with the server version string.
+        "Attach" | "Name" => {}
+
+        "AppVersion" => {
+            let _ = socket.send(Message::Text(
+                json!({ "Results": ["usb2snes-rs-1.0"] }).to_string(),
+            ));
+        }
+
+        // GetAddress <offset> <size> -> binary frame with the bytes read back. The client blocks
+        // waiting for this frame, so on a device error we still reply (with an empty frame) rather
+        // than leaving it hung.
+        "GetAddress" => {
+            let offset = ops.first().map(|o| parse_hex(o)).unwrap_or(0);
+            let size = ops.get(1).map(|s| parse_hex(s)).unwrap_or(0);
+
+            let data = device.get_memory(offset, size).unwrap_or_default();
+            let _ = socket.send(Message::Binary(data));
+        }
+
+        // PutAddress <offset> <size> -> the payload follows as one or more binary frames.
+        "PutAddress" => {
+            let offset = ops.first().map(|o| parse_hex(o)).unwrap_or(0);
+            let size = ops.get(1).map(|s| parse_hex(s)).unwrap_or(0);
+
+            let mut payload = Vec::with_capacity(size as usize);
+
+            while (payload.len() as u32) < size {
+                match socket.read() {
+                    Ok(Message::Binary(chunk)) => payload.extend_from_slice(&chunk),
+                    Ok(Message::Close(_)) | Err(_) => return Ok(()),
+                    _ => continue,
+                }
+            }
+
+            let _ = device.put_memory(offset, &payload);
+        }
+
+        "Info" => {
+            if let Ok(info) = device.info() {
+                let results = json!({
+                    "Results": [
+                        info.firmware_version,
+                        info.device_name,
+                        info.rom_name,
+                    ]
+                });
+                let _ = socket.send(Message::Text(results.to_string()));
+            }
+        }
+
+        "Boot" => {
+            if let Some(path) = ops.first() {
+                let _ = device.boot(path);
+            }
+        }
+
+        "Reset" => {
+            let _ = device.reset();
+        }
+
+        // List <path> -> the SD card directory listing.
+        "List" => {
+            let path = ops.first().map(|p| p.as_str()).unwrap_or("/");
+            if let Ok(entries) = device.ls(path) {
+                let mut results = Vec::new();
+                for entry in entries {
+                    // QUsb2Snes reports each entry as a [type, name] pair.
+                    results.push(json!(if entry.is_dir { 0 } else { 1 }));
+                    results.push(json!(entry.name));
+                }
+                let _ = socket.send(Message::Text(json!({ "Results": results }).to_string()));
+            }
+        }
+
+        other => {
+            println!("unhandled opcode {}", other);
+        }
+    }
+
+    Ok(())
+}